@@ -1,6 +1,14 @@
 use anyhow::{Context, Result};
 use clap::Parser;
+use futures::future::{BoxFuture, Shared};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
 use tokio::time::{timeout, Duration};
 use tracing::{info, Level};
 use tracing_subscriber::EnvFilter;
@@ -27,6 +35,65 @@ struct Args {
     /// Optional custom User-Agent header
     #[arg(long, default_value = "url-audit/0.1")]
     user_agent: String,
+
+    /// Write a percentile/status-class summary report to this path
+    #[arg(long)]
+    summary: Option<String>,
+
+    /// Max redirects to follow before giving up
+    #[arg(long, default_value_t = 10)]
+    max_redirects: u32,
+
+    /// Download and SHA-256 the response body instead of trusting Content-Length
+    #[arg(long)]
+    hash: bool,
+
+    /// Abort the body download past this many bytes (only applies with --hash)
+    #[arg(long, default_value_t = 20 * 1024 * 1024)]
+    max_body_bytes: u64,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+
+    /// Resume a --format ndjson run by skipping one input row per row already present in the output file
+    #[arg(long)]
+    resume: bool,
+
+    /// Retry connection errors, timeouts, and 429/5xx responses this many times
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Base delay for exponential backoff between retries
+    #[arg(long, default_value_t = 200)]
+    retry_base_ms: u64,
+
+    /// Extra header to send with every request ("Name: Value"), may be repeated
+    #[arg(long = "header", value_name = "NAME: VALUE")]
+    headers: Vec<String>,
+
+    /// Bearer token to send in the Authorization header
+    #[arg(long, conflicts_with = "auth_bearer_env")]
+    auth_bearer: Option<String>,
+
+    /// Name of an env var holding the bearer token, to keep it out of shell history
+    #[arg(long, conflicts_with = "auth_bearer")]
+    auth_bearer_env: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Json,
+    Ndjson,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Ndjson => write!(f, "ndjson"),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,12 +101,276 @@ struct InRow {
     url: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct OutRow {
     url: String,
     status: Option<u16>,
     len: Option<u64>,
     error: Option<String>,
+    elapsed_ms: u64,
+    final_url: Option<String>,
+    redirect_count: u32,
+    host: Option<String>,
+    sha256: Option<String>,
+    content_type: Option<String>,
+    attempts: u32,
+}
+
+/// Result of fetching a single URL, decoupled from the row(s) it feeds.
+///
+/// `reqwest::Error` isn't `Clone`, so the error is stringified eagerly here
+/// to let a single fetch's result be shared across every duplicate URL.
+#[derive(Debug, Clone)]
+struct FetchResult {
+    status: Option<u16>,
+    len: Option<u64>,
+    error: Option<String>,
+    elapsed_ms: u64,
+    final_url: Option<String>,
+    redirect_count: u32,
+    host: Option<String>,
+    sha256: Option<String>,
+    content_type: Option<String>,
+    attempts: u32,
+}
+
+impl FetchResult {
+    /// A terminal result with no body information, used for connection
+    /// errors, timeouts, and redirect-handling failures.
+    fn terminal(
+        status: Option<u16>,
+        error: impl Into<String>,
+        elapsed_ms: u64,
+        final_url: Option<String>,
+        redirect_count: u32,
+    ) -> Self {
+        FetchResult {
+            status,
+            len: None,
+            error: Some(error.into()),
+            elapsed_ms,
+            final_url,
+            redirect_count,
+            host: None,
+            sha256: None,
+            content_type: None,
+            attempts: 1,
+        }
+    }
+}
+
+impl OutRow {
+    fn from_result(url: String, r: &FetchResult) -> Self {
+        OutRow {
+            url,
+            status: r.status,
+            len: r.len,
+            error: r.error.clone(),
+            elapsed_ms: r.elapsed_ms,
+            final_url: r.final_url.clone(),
+            redirect_count: r.redirect_count,
+            host: r.host.clone(),
+            sha256: r.sha256.clone(),
+            content_type: r.content_type.clone(),
+            attempts: r.attempts,
+        }
+    }
+}
+
+/// Status-class and outcome counts for a run, used by the `--summary` report.
+#[derive(Debug, Default, Serialize)]
+struct StatusClassCounts {
+    #[serde(rename = "2xx")]
+    two_xx: u64,
+    #[serde(rename = "3xx")]
+    three_xx: u64,
+    #[serde(rename = "4xx")]
+    four_xx: u64,
+    #[serde(rename = "5xx")]
+    five_xx: u64,
+    timeout: u64,
+    error: u64,
+}
+
+/// Latency percentiles (milliseconds) over successful requests.
+#[derive(Debug, Default, Serialize)]
+struct LatencyPercentiles {
+    p50: Option<u64>,
+    p90: Option<u64>,
+    p99: Option<u64>,
+    max: Option<u64>,
+}
+
+impl LatencyPercentiles {
+    /// `elapsed` must already be sorted ascending.
+    fn from_sorted(elapsed: &[u64]) -> Self {
+        if elapsed.is_empty() {
+            return Self::default();
+        }
+        let at = |p: f64| -> u64 {
+            let idx = ((p / 100.0) * (elapsed.len() - 1) as f64).round() as usize;
+            elapsed[idx]
+        };
+        LatencyPercentiles {
+            p50: Some(at(50.0)),
+            p90: Some(at(90.0)),
+            p99: Some(at(99.0)),
+            max: elapsed.last().copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod latency_percentiles_tests {
+    use super::LatencyPercentiles;
+
+    #[test]
+    fn empty_input_yields_all_none() {
+        let p = LatencyPercentiles::from_sorted(&[]);
+        assert_eq!(p.p50, None);
+        assert_eq!(p.p90, None);
+        assert_eq!(p.p99, None);
+        assert_eq!(p.max, None);
+    }
+
+    #[test]
+    fn single_value_is_every_percentile() {
+        let p = LatencyPercentiles::from_sorted(&[42]);
+        assert_eq!(p.p50, Some(42));
+        assert_eq!(p.p90, Some(42));
+        assert_eq!(p.p99, Some(42));
+        assert_eq!(p.max, Some(42));
+    }
+
+    #[test]
+    fn max_is_always_the_last_element() {
+        let elapsed: Vec<u64> = (1..=100).collect();
+        let p = LatencyPercentiles::from_sorted(&elapsed);
+        assert_eq!(p.max, Some(100));
+    }
+
+    #[test]
+    fn percentiles_index_into_sorted_input() {
+        // 1..=100 sorted ascending: p50 -> index round(0.50*99)=50 -> value 51
+        let elapsed: Vec<u64> = (1..=100).collect();
+        let p = LatencyPercentiles::from_sorted(&elapsed);
+        assert_eq!(p.p50, Some(51));
+        assert_eq!(p.p90, Some(90));
+        assert_eq!(p.p99, Some(99));
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    started_at_unix_ms: u128,
+    duration_ms: u128,
+    concurrency: usize,
+    timeout_secs: u64,
+    total: usize,
+    status_classes: StatusClassCounts,
+    latency_ms: LatencyPercentiles,
+}
+
+/// Accumulates `--summary` stats row-by-row, so the ndjson path can fold
+/// them in as rows stream out instead of holding the whole run in memory.
+#[derive(Default)]
+struct SummaryAccumulator {
+    total: usize,
+    classes: StatusClassCounts,
+    elapsed: Vec<u64>,
+}
+
+impl SummaryAccumulator {
+    fn add(&mut self, row: &OutRow) {
+        self.total += 1;
+        match (row.status, &row.error) {
+            (Some(status), None) => {
+                self.elapsed.push(row.elapsed_ms);
+                match status / 100 {
+                    2 => self.classes.two_xx += 1,
+                    3 => self.classes.three_xx += 1,
+                    4 => self.classes.four_xx += 1,
+                    5 => self.classes.five_xx += 1,
+                    _ => self.classes.error += 1,
+                }
+            }
+            // fetch_once never returns a "successful" 3xx: redirects are
+            // either followed to a final status or exhausted into a terminal
+            // error (too many redirects / loop / missing Location), which
+            // still carries the last-seen 3xx status. Count those as 3xx too,
+            // rather than losing them in the generic error bucket.
+            (Some(status), Some(_)) if status / 100 == 3 => self.classes.three_xx += 1,
+            (_, Some(err)) if err == "timeout" => self.classes.timeout += 1,
+            _ => self.classes.error += 1,
+        }
+    }
+
+    fn finish(
+        mut self,
+        started_at_unix_ms: u128,
+        duration_ms: u128,
+        concurrency: usize,
+        timeout_secs: u64,
+    ) -> Summary {
+        self.elapsed.sort_unstable();
+        Summary {
+            started_at_unix_ms,
+            duration_ms,
+            concurrency,
+            timeout_secs,
+            total: self.total,
+            status_classes: self.classes,
+            latency_ms: LatencyPercentiles::from_sorted(&self.elapsed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod summary_accumulator_tests {
+    use super::{OutRow, SummaryAccumulator};
+
+    fn row(status: Option<u16>, error: Option<&str>) -> OutRow {
+        OutRow {
+            url: "http://example.test".to_string(),
+            status,
+            len: None,
+            error: error.map(str::to_owned),
+            elapsed_ms: 5,
+            final_url: None,
+            redirect_count: 0,
+            host: None,
+            sha256: None,
+            content_type: None,
+            attempts: 1,
+        }
+    }
+
+    #[test]
+    fn exhausted_redirects_count_as_3xx_not_generic_error() {
+        let mut acc = SummaryAccumulator::default();
+        acc.add(&row(Some(301), Some("too many redirects")));
+        acc.add(&row(Some(302), Some("redirect loop")));
+        acc.add(&row(Some(303), Some("redirect without a valid Location")));
+        assert_eq!(acc.classes.three_xx, 3);
+        assert_eq!(acc.classes.error, 0);
+    }
+
+    #[test]
+    fn successful_3xx_is_unreachable_but_would_also_count_as_3xx() {
+        let mut acc = SummaryAccumulator::default();
+        acc.add(&row(Some(301), None));
+        assert_eq!(acc.classes.three_xx, 1);
+    }
+
+    #[test]
+    fn timeouts_and_other_errors_are_unaffected() {
+        let mut acc = SummaryAccumulator::default();
+        acc.add(&row(None, Some("timeout")));
+        acc.add(&row(None, Some("connection refused")));
+        assert_eq!(acc.classes.timeout, 1);
+        assert_eq!(acc.classes.error, 1);
+        assert_eq!(acc.classes.three_xx, 0);
+    }
 }
 
 #[tokio::main]
@@ -51,9 +382,17 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     info!("reading {}", &args.input);
 
+    let started_at_unix_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let run_start = Instant::now();
+
     let client = reqwest::Client::builder()
         .user_agent(args.user_agent.clone())
         .tcp_nodelay(true)
+        .redirect(reqwest::redirect::Policy::none())
+        .default_headers(build_default_headers(&args)?)
         .build()
         .context("building HTTP client")?;
 
@@ -69,74 +408,794 @@ async fn main() -> Result<()> {
     }
     info!(count = urls.len(), "loaded URLs");
 
+    let mut existing_rows: Vec<OutRow> = Vec::new();
+    if args.resume && args.format == OutputFormat::Ndjson {
+        existing_rows = read_existing_rows(&args.output);
+        if !existing_rows.is_empty() {
+            let mut remaining: HashMap<String, usize> = HashMap::new();
+            for row in &existing_rows {
+                *remaining.entry(row.url.clone()).or_insert(0) += 1;
+            }
+
+            let before = urls.len();
+            // Skip one occurrence per already-written row, not every row for
+            // a URL that appears more than once: chunk0-1's single-flight
+            // coalescing can emit several OutRows for the same URL (one per
+            // duplicate CSV row), and an interrupted run may have flushed
+            // only some of them.
+            urls.retain(|u| match remaining.get_mut(u) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            });
+            info!(
+                skipped = before - urls.len(),
+                "resuming: skipping rows already present in {}", &args.output
+            );
+        }
+    }
+
     let sem = std::sync::Arc::new(tokio::sync::Semaphore::new(args.concurrency));
-    let mut tasks = Vec::with_capacity(urls.len());
-    for url in urls {
+    let tmo = Duration::from_secs(args.timeout);
+
+    let waiters = build_waiters(&urls, &sem, |url| {
         let client = client.clone();
-        let permit = sem.clone().acquire_owned().await?;
-        let tmo = Duration::from_secs(args.timeout);
-        tasks.push(tokio::spawn(async move {
-            let _permit = permit;
-            fetch_row(&client, url, tmo).await
-        }));
+        let max_redirects = args.max_redirects;
+        let hash_mode = args.hash;
+        let max_body_bytes = args.max_body_bytes;
+        let retries = args.retries;
+        let retry_base_ms = args.retry_base_ms;
+        async move {
+            fetch_row(
+                &client,
+                url,
+                tmo,
+                max_redirects,
+                hash_mode,
+                max_body_bytes,
+                retries,
+                retry_base_ms,
+            )
+            .await
+        }
+    })
+    .await?;
+
+    match args.format {
+        OutputFormat::Json => {
+            let mut out = Vec::with_capacity(waiters.len());
+            for (url, shared) in waiters {
+                out.push(OutRow::from_result(url, &shared.await));
+            }
+
+            std::fs::write(&args.output, serde_json::to_vec_pretty(&out)?)
+                .with_context(|| format!("writing {}", &args.output))?;
+            info!("wrote {} rows to {}", out.len(), &args.output);
+
+            if let Some(summary_path) = &args.summary {
+                let summary = build_summary(
+                    &out,
+                    started_at_unix_ms,
+                    run_start.elapsed().as_millis(),
+                    args.concurrency,
+                    args.timeout,
+                );
+                std::fs::write(summary_path, serde_json::to_vec_pretty(&summary)?)
+                    .with_context(|| format!("writing summary {summary_path}"))?;
+                info!("wrote summary to {}", summary_path);
+            }
+        }
+        OutputFormat::Ndjson => {
+            stream_ndjson(
+                waiters,
+                existing_rows,
+                &args.output,
+                args.resume,
+                args.summary.as_deref(),
+                started_at_unix_ms,
+                run_start.elapsed().as_millis(),
+                args.concurrency,
+                args.timeout,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Single-flight: at most one fetch per unique URL. Duplicate rows await a
+/// clone of the same shared future instead of spawning a new request, and
+/// the semaphore permit is acquired only once per unique URL, just before its
+/// task is spawned, and held by that task until the fetch completes. Since
+/// each acquire waits for a free permit, this returns only once every unique
+/// URL has been spawned. `spawn_fetch` is called exactly once per unique URL,
+/// with the owned URL string to fetch; its returned future does the actual work.
+async fn build_waiters<Fut>(
+    urls: &[String],
+    sem: &std::sync::Arc<tokio::sync::Semaphore>,
+    mut spawn_fetch: impl FnMut(String) -> Fut,
+) -> Result<Vec<(String, Shared<BoxFuture<'static, FetchResult>>)>>
+where
+    Fut: std::future::Future<Output = FetchResult> + Send + 'static,
+{
+    let mut inflight: HashMap<String, Shared<BoxFuture<'static, FetchResult>>> = HashMap::new();
+    let mut waiters: Vec<(String, Shared<BoxFuture<'static, FetchResult>>)> =
+        Vec::with_capacity(urls.len());
+
+    for url in urls {
+        let shared = match inflight.get(url) {
+            Some(shared) => shared.clone(),
+            None => {
+                let permit = sem.clone().acquire_owned().await?;
+                let fut = spawn_fetch(url.clone());
+                let shared: Shared<BoxFuture<'static, FetchResult>> = tokio::spawn(async move {
+                    let _permit = permit;
+                    fut.await
+                })
+                .map(|r| {
+                    r.unwrap_or_else(|e| {
+                        FetchResult::terminal(None, format!("join error: {e}"), 0, None, 0)
+                    })
+                })
+                .boxed()
+                .shared();
+                inflight.insert(url.clone(), shared.clone());
+                shared
+            }
+        };
+        waiters.push((url.clone(), shared));
+    }
+
+    Ok(waiters)
+}
+
+#[cfg(test)]
+mod build_waiters_tests {
+    use super::{build_waiters, FetchResult};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn duplicate_urls_share_a_single_fetch() {
+        let urls: Vec<String> = ["a", "b", "a", "c", "b", "a"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let sem = Arc::new(tokio::sync::Semaphore::new(2));
+        let spawn_count = Arc::new(AtomicUsize::new(0));
+
+        let waiters = build_waiters(&urls, &sem, {
+            let spawn_count = spawn_count.clone();
+            move |url| {
+                spawn_count.fetch_add(1, Ordering::SeqCst);
+                async move { FetchResult::terminal(None, url, 0, None, 0) }
+            }
+        })
+        .await
+        .expect("build_waiters should not fail");
+
+        // Exactly one spawn per unique URL, regardless of how many rows
+        // reference it.
+        assert_eq!(spawn_count.load(Ordering::SeqCst), 3);
+
+        // Output order and count matches the original row list.
+        assert_eq!(waiters.len(), urls.len());
+        for (expected, (url, _)) in urls.iter().zip(waiters.iter()) {
+            assert_eq!(expected, url);
+        }
+
+        // Every waiter for the same URL resolves to the same fetch result
+        // (the "error" field doubles as the URL tag here for assertion).
+        for (url, shared) in &waiters {
+            let result = shared.clone().await;
+            assert_eq!(result.error.as_deref(), Some(url.as_str()));
+        }
     }
+}
+
+/// Drive `waiters` through a `FuturesUnordered` and append each row, in
+/// completion order, as an NDJSON line via a dedicated writer task fed over an
+/// mpsc channel. With `append` set the file is appended to instead of
+/// truncated, which is how `--resume` picks up an interrupted run.
+///
+/// `waiters` comes from `build_waiters`, which returns only after every unique
+/// URL has been spawned; by then all but the last concurrency window of
+/// fetches have finished and their results are held in memory. Nothing is
+/// written before that point, so memory grows with the number of input rows,
+/// not the concurrency window.
+///
+/// `existing_rows` are the rows a `--resume` run already found on disk (and
+/// therefore won't re-fetch): they're folded into the `--summary` stats so a
+/// resumed run's summary covers the whole audit, not just the newly-fetched
+/// tail.
+#[allow(clippy::too_many_arguments)]
+async fn stream_ndjson(
+    waiters: Vec<(String, Shared<BoxFuture<'static, FetchResult>>)>,
+    existing_rows: Vec<OutRow>,
+    output_path: &str,
+    append: bool,
+    summary_path: Option<&str>,
+    started_at_unix_ms: u128,
+    duration_ms: u128,
+    concurrency: usize,
+    timeout_secs: u64,
+) -> Result<()> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<OutRow>(concurrency.max(1));
+
+    let output_path_owned = output_path.to_string();
+    let writer = tokio::spawn(async move {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&output_path_owned)
+            .await
+            .with_context(|| format!("opening {output_path_owned}"))?;
 
-    let mut out = Vec::with_capacity(tasks.len());
-    for t in tasks {
-        match t.await {
-            Ok(row) => out.push(row),
-            Err(e) => out.push(OutRow {
-                url: "<join-error>".into(),
-                status: None,
-                len: None,
-                error: Some(format!("join error: {e}")),
-            }),
+        let mut written = 0usize;
+        while let Some(row) = rx.recv().await {
+            let mut line = serde_json::to_vec(&row)?;
+            line.push(b'\n');
+            file.write_all(&line)
+                .await
+                .with_context(|| format!("writing {output_path_owned}"))?;
+            written += 1;
         }
+        file.flush()
+            .await
+            .with_context(|| format!("flushing {output_path_owned}"))?;
+        Ok::<usize, anyhow::Error>(written)
+    });
+
+    let mut pending: FuturesUnordered<_> = waiters
+        .into_iter()
+        .map(|(url, shared)| async move { OutRow::from_result(url, &shared.await) })
+        .collect();
+
+    let mut acc = SummaryAccumulator::default();
+    for row in &existing_rows {
+        acc.add(row);
     }
+    while let Some(row) = pending.next().await {
+        acc.add(&row);
+        tx.send(row)
+            .await
+            .context("sending row to ndjson writer task")?;
+    }
+    drop(tx);
+
+    let written = writer.await.context("joining ndjson writer task")??;
+    info!("wrote {written} rows to {output_path}");
 
-    std::fs::write(&args.output, serde_json::to_vec_pretty(&out)?)
-        .with_context(|| format!("writing {}", &args.output))?;
+    if let Some(summary_path) = summary_path {
+        let summary = acc.finish(started_at_unix_ms, duration_ms, concurrency, timeout_secs);
+        std::fs::write(summary_path, serde_json::to_vec_pretty(&summary)?)
+            .with_context(|| format!("writing summary {summary_path}"))?;
+        info!("wrote summary to {summary_path}");
+    }
 
-    info!("wrote {} rows to {}", out.len(), &args.output);
     Ok(())
 }
 
-async fn fetch_row(client: &reqwest::Client, url: String, tmo: Duration) -> OutRow {
-    // Keep one clone for the timeout case
-    let url_for_timeout = url.clone();
+fn build_summary(
+    out: &[OutRow],
+    started_at_unix_ms: u128,
+    duration_ms: u128,
+    concurrency: usize,
+    timeout_secs: u64,
+) -> Summary {
+    let mut acc = SummaryAccumulator::default();
+    for row in out {
+        acc.add(row);
+    }
+    acc.finish(started_at_unix_ms, duration_ms, concurrency, timeout_secs)
+}
+
+/// Read the rows a `--resume` run already wrote, used both to figure out how
+/// many occurrences of each URL are already done (so duplicate-URL rows
+/// aren't all skipped together) and to fold their stats into `--summary`.
+fn read_existing_rows(ndjson_path: &str) -> Vec<OutRow> {
+    let Ok(content) = std::fs::read_to_string(ndjson_path) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str::<OutRow>(line).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod read_existing_rows_tests {
+    use super::{read_existing_rows, OutRow, SummaryAccumulator};
+
+    fn row(url: &str, status: u16, elapsed_ms: u64) -> OutRow {
+        OutRow {
+            url: url.to_string(),
+            status: Some(status),
+            len: None,
+            error: None,
+            elapsed_ms,
+            final_url: None,
+            redirect_count: 0,
+            host: None,
+            sha256: None,
+            content_type: None,
+            attempts: 1,
+        }
+    }
+
+    #[test]
+    fn missing_file_yields_no_rows() {
+        assert!(read_existing_rows("/nonexistent/url-audit-test.ndjson").is_empty());
+    }
+
+    #[test]
+    fn round_trips_previously_written_rows_and_folds_into_summary() {
+        let path = std::env::temp_dir().join(format!(
+            "url-audit-read-existing-rows-test-{}.ndjson",
+            std::process::id()
+        ));
+        let rows = [row("http://a.test", 200, 10), row("http://b.test", 200, 20)];
+        let content: String = rows
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap() + "\n")
+            .collect();
+        std::fs::write(&path, content).unwrap();
+
+        let loaded = read_existing_rows(path.to_str().unwrap());
+        assert_eq!(loaded.len(), 2);
+
+        // This is what stream_ndjson does to avoid under-reporting a
+        // --resume --summary run: fold the already-written rows in before
+        // any newly-fetched ones.
+        let mut acc = SummaryAccumulator::default();
+        for row in &loaded {
+            acc.add(row);
+        }
+        assert_eq!(acc.total, 2);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
+
+/// Ceiling on the computed backoff delay, before jitter is added.
+const RETRY_BACKOFF_CEILING_MS: u64 = 30_000;
+
+/// One attempt's outcome, tagged with whether it's worth retrying and any
+/// server-requested delay. Kept separate from `FetchResult` since that
+/// distinction doesn't belong in the final output.
+struct AttemptOutcome {
+    result: FetchResult,
+    retryable: bool,
+    retry_after_ms: Option<u64>,
+}
+
+/// Build the headers every request carries: `--header` flags plus, if set,
+/// a bearer `Authorization` header sourced from `--auth-bearer` or the env
+/// var named by `--auth-bearer-env` (kept out of shell history that way).
+fn build_default_headers(args: &Args) -> Result<reqwest::header::HeaderMap> {
+    let mut headers = reqwest::header::HeaderMap::new();
+
+    for raw in &args.headers {
+        let (name, value) = raw
+            .split_once(':')
+            .with_context(|| format!("invalid --header {raw:?}, expected \"Name: Value\""))?;
+        let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes())
+            .with_context(|| format!("invalid header name in {raw:?}"))?;
+        let value = reqwest::header::HeaderValue::from_str(value.trim())
+            .with_context(|| format!("invalid header value in {raw:?}"))?;
+        // append, not insert: --header "may be repeated", so the same name
+        // given twice should send both values rather than silently keeping
+        // only the last.
+        headers.append(name, value);
+    }
+
+    let bearer = if let Some(token) = &args.auth_bearer {
+        Some(token.clone())
+    } else if let Some(var) = &args.auth_bearer_env {
+        Some(
+            std::env::var(var)
+                .with_context(|| format!("reading bearer token from env var {var}"))?,
+        )
+    } else {
+        None
+    };
+
+    if let Some(token) = bearer {
+        let value = reqwest::header::HeaderValue::from_str(&format!("Bearer {token}"))
+            .context("invalid bearer token")?;
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod build_default_headers_tests {
+    use super::{build_default_headers, Args, OutputFormat};
+
+    fn base_args() -> Args {
+        Args {
+            input: "in.csv".to_string(),
+            output: "report.json".to_string(),
+            concurrency: 32,
+            timeout: 10,
+            user_agent: "url-audit/0.1".to_string(),
+            summary: None,
+            max_redirects: 10,
+            hash: false,
+            max_body_bytes: 20 * 1024 * 1024,
+            format: OutputFormat::Json,
+            resume: false,
+            retries: 0,
+            retry_base_ms: 200,
+            headers: Vec::new(),
+            auth_bearer: None,
+            auth_bearer_env: None,
+        }
+    }
+
+    #[test]
+    fn missing_colon_is_an_error() {
+        let mut args = base_args();
+        args.headers = vec!["X-Trace-no-colon".to_string()];
+        assert!(build_default_headers(&args).is_err());
+    }
+
+    #[test]
+    fn invalid_header_name_is_an_error() {
+        let mut args = base_args();
+        args.headers = vec!["X Trace Bad: value".to_string()];
+        assert!(build_default_headers(&args).is_err());
+    }
+
+    #[test]
+    fn invalid_header_value_is_an_error() {
+        let mut args = base_args();
+        args.headers = vec!["X-Trace: bad\nvalue".to_string()];
+        assert!(build_default_headers(&args).is_err());
+    }
+
+    #[test]
+    fn missing_bearer_env_var_is_an_error() {
+        let mut args = base_args();
+        args.auth_bearer_env = Some("URL_AUDIT_TEST_VAR_THAT_DOES_NOT_EXIST".to_string());
+        assert!(build_default_headers(&args).is_err());
+    }
+
+    #[test]
+    fn repeated_header_name_keeps_every_value() {
+        let mut args = base_args();
+        args.headers = vec!["X-Trace: a".to_string(), "X-Trace: b".to_string()];
+        let headers = build_default_headers(&args).expect("headers should build");
+        let values: Vec<&str> = headers
+            .get_all("X-Trace")
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(values, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn bearer_token_sets_authorization_header() {
+        let mut args = base_args();
+        args.auth_bearer = Some("secret".to_string());
+        let headers = build_default_headers(&args).expect("headers should build");
+        assert_eq!(
+            headers.get(reqwest::header::AUTHORIZATION).unwrap(),
+            "Bearer secret"
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn fetch_row(
+    client: &reqwest::Client,
+    url: String,
+    tmo: Duration,
+    max_redirects: u32,
+    hash_mode: bool,
+    max_body_bytes: u64,
+    retries: u32,
+    retry_base_ms: u64,
+) -> FetchResult {
+    let mut attempt: u32 = 0;
+
+    loop {
+        attempt += 1;
+        let attempt_started = Instant::now();
+        let outcome = match timeout(
+            tmo,
+            fetch_once(
+                client,
+                url.clone(),
+                max_redirects,
+                hash_mode,
+                max_body_bytes,
+            ),
+        )
+        .await
+        {
+            Ok(outcome) => outcome,
+            Err(_) => AttemptOutcome {
+                result: FetchResult::terminal(None, "timeout", 0, Some(url.clone()), 0),
+                retryable: true,
+                retry_after_ms: None,
+            },
+        };
+
+        let mut result = outcome.result;
+        // Only the returned attempt's own network time, so `--retries` doesn't
+        // inflate `elapsed_ms` with prior failed attempts or backoff sleeps.
+        result.elapsed_ms = attempt_started.elapsed().as_millis() as u64;
+        result.attempts = attempt;
+
+        if attempt > retries || !outcome.retryable {
+            return result;
+        }
+
+        let delay_ms = outcome
+            .retry_after_ms
+            .unwrap_or_else(|| backoff_with_jitter_ms(retry_base_ms, attempt));
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+    }
+}
+
+/// `base_ms * 2^(attempt-1)`, capped at `RETRY_BACKOFF_CEILING_MS`, plus
+/// uniform jitter in `[0, capped/2)` to avoid synchronizing retries across
+/// concurrent tasks.
+fn backoff_with_jitter_ms(base_ms: u64, attempt: u32) -> u64 {
+    let shift = attempt.saturating_sub(1).min(16);
+    let backoff = base_ms
+        .saturating_mul(1u64 << shift)
+        .min(RETRY_BACKOFF_CEILING_MS);
+    let jitter = rand::thread_rng().gen_range(0..(backoff / 2).max(1));
+    backoff + jitter
+}
+
+#[cfg(test)]
+mod backoff_with_jitter_ms_tests {
+    use super::{backoff_with_jitter_ms, RETRY_BACKOFF_CEILING_MS};
+
+    #[test]
+    fn result_is_always_at_least_the_base_delay() {
+        for attempt in 1..=20 {
+            let delay = backoff_with_jitter_ms(200, attempt);
+            assert!(delay >= 200, "attempt {attempt}: delay {delay} < base 200");
+        }
+    }
+
+    #[test]
+    fn jitter_never_doubles_the_backoff() {
+        // jitter is drawn from [0, backoff/2), so delay < backoff * 1.5
+        for attempt in 1u32..=20 {
+            let shift = attempt.saturating_sub(1).min(16);
+            let backoff = 200u64
+                .saturating_mul(1u64 << shift)
+                .min(RETRY_BACKOFF_CEILING_MS);
+            for _ in 0..50 {
+                let delay = backoff_with_jitter_ms(200, attempt);
+                assert!(delay < backoff + backoff / 2 + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn grows_with_attempt_number_until_the_ceiling() {
+        // The delay floor (backoff with zero jitter) should be non-decreasing
+        // as the attempt number increases, up to the ceiling, and every
+        // sampled delay should sit at or above that floor.
+        let mut prev_floor = 0u64;
+        for attempt in 1u32..=10 {
+            let shift = attempt.saturating_sub(1).min(16);
+            let floor = 100u64
+                .saturating_mul(1u64 << shift)
+                .min(RETRY_BACKOFF_CEILING_MS);
+            assert!(floor >= prev_floor);
+            prev_floor = floor;
+
+            let delay = backoff_with_jitter_ms(100, attempt);
+            assert!(delay >= floor);
+        }
+    }
+
+    #[test]
+    fn respects_the_ceiling_for_large_attempt_numbers() {
+        for _ in 0..20 {
+            let delay = backoff_with_jitter_ms(1_000, 64);
+            assert!(
+                delay < RETRY_BACKOFF_CEILING_MS + RETRY_BACKOFF_CEILING_MS / 2 + 1,
+                "delay {delay} exceeds ceiling + max jitter"
+            );
+        }
+    }
+}
+
+async fn fetch_once(
+    client: &reqwest::Client,
+    url: String,
+    max_redirects: u32,
+    hash_mode: bool,
+    max_body_bytes: u64,
+) -> AttemptOutcome {
+    let mut current = url;
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut redirect_count: u32 = 0;
+
+    loop {
+        let resp = match client.get(&current).send().await {
+            Ok(resp) => resp,
+            Err(e) => {
+                return AttemptOutcome {
+                    result: FetchResult::terminal(
+                        None,
+                        e.to_string(),
+                        0,
+                        Some(current),
+                        redirect_count,
+                    ),
+                    retryable: true,
+                    retry_after_ms: None,
+                };
+            }
+        };
 
-    let fut = async {
-        match client.get(&url).send().await {
-            Ok(resp) => {
-                let status = resp.status().as_u16();
+        let status = resp.status();
+        if !status.is_redirection() {
+            let retryable = status.as_u16() == 429 || status.is_server_error();
+            let retry_after_ms = retryable
+                .then(|| {
+                    resp.headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|s| s.parse::<u64>().ok())
+                        .map(|secs| secs * 1000)
+                })
+                .flatten();
+
+            let host = reqwest::Url::parse(&current)
+                .ok()
+                .and_then(|u| u.domain().map(str::to_owned));
+            let content_type = resp
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned);
+
+            let result = if hash_mode {
+                match hash_body(resp, max_body_bytes).await {
+                    Ok((len, sha256)) => FetchResult {
+                        status: Some(status.as_u16()),
+                        len: Some(len),
+                        error: None,
+                        elapsed_ms: 0,
+                        final_url: Some(current),
+                        redirect_count,
+                        host,
+                        sha256: Some(sha256),
+                        content_type,
+                        attempts: 1,
+                    },
+                    Err(err) => FetchResult {
+                        status: Some(status.as_u16()),
+                        len: None,
+                        error: Some(err),
+                        elapsed_ms: 0,
+                        final_url: Some(current),
+                        redirect_count,
+                        host,
+                        sha256: None,
+                        content_type,
+                        attempts: 1,
+                    },
+                }
+            } else {
                 let len = resp
                     .headers()
                     .get(reqwest::header::CONTENT_LENGTH)
                     .and_then(|v| v.to_str().ok())
                     .and_then(|s| s.parse::<u64>().ok());
-                OutRow {
-                    url,
-                    status: Some(status),
+                FetchResult {
+                    status: Some(status.as_u16()),
                     len,
                     error: None,
+                    elapsed_ms: 0,
+                    final_url: Some(current),
+                    redirect_count,
+                    host,
+                    sha256: None,
+                    content_type: None,
+                    attempts: 1,
                 }
+            };
+
+            return AttemptOutcome {
+                result,
+                retryable,
+                retry_after_ms,
+            };
+        }
+
+        if redirect_count >= max_redirects {
+            return AttemptOutcome {
+                result: FetchResult::terminal(
+                    Some(status.as_u16()),
+                    "too many redirects",
+                    0,
+                    Some(current),
+                    redirect_count,
+                ),
+                retryable: false,
+                retry_after_ms: None,
+            };
+        }
+
+        let location = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok());
+        let next = match location.and_then(|loc| {
+            reqwest::Url::parse(&current)
+                .ok()
+                .and_then(|base| base.join(loc).ok())
+        }) {
+            Some(next) => next.to_string(),
+            None => {
+                return AttemptOutcome {
+                    result: FetchResult::terminal(
+                        Some(status.as_u16()),
+                        "redirect without a valid Location",
+                        0,
+                        Some(current),
+                        redirect_count,
+                    ),
+                    retryable: false,
+                    retry_after_ms: None,
+                };
             }
-            Err(e) => OutRow {
-                url,
-                status: None,
-                len: None,
-                error: Some(e.to_string()),
-            },
+        };
+
+        if !visited.insert(current.clone()) {
+            return AttemptOutcome {
+                result: FetchResult::terminal(
+                    Some(status.as_u16()),
+                    "redirect loop",
+                    0,
+                    Some(current),
+                    redirect_count,
+                ),
+                retryable: false,
+                retry_after_ms: None,
+            };
         }
-    };
 
-    match tokio::time::timeout(tmo, fut).await {
-        Ok(row) => row,
-        Err(_) => OutRow {
-            url: url_for_timeout,
-            status: None,
-            len: None,
-            error: Some("timeout".into()),
-        },
+        redirect_count += 1;
+        current = next;
+    }
+}
+
+/// Stream the body through a SHA-256 hasher, counting bytes as they arrive
+/// instead of trusting `Content-Length`. Aborts once `max_body_bytes` is
+/// exceeded.
+async fn hash_body(
+    resp: reqwest::Response,
+    max_body_bytes: u64,
+) -> std::result::Result<(u64, String), String> {
+    let mut hasher = Sha256::new();
+    let mut total: u64 = 0;
+    let mut stream = resp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        total += chunk.len() as u64;
+        if total > max_body_bytes {
+            return Err("body exceeded limit".to_string());
+        }
+        hasher.update(&chunk);
     }
+    Ok((total, format!("{:x}", hasher.finalize())))
 }